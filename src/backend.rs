@@ -0,0 +1,467 @@
+//! Storage backends for the bytes `SimpleFS` keeps in `source_dir`.
+//!
+//! `SimpleFS` always presents cleartext, uncompressed data to the kernel;
+//! a `Backend` decides what actually sits on disk underneath. Blobs are
+//! split into fixed-size chunks so random reads only have to decode the
+//! chunks they actually touch, instead of the whole file.
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{chown, FileExt, MetadataExt};
+use std::path::Path;
+
+/// Logical (cleartext) chunk size. Chosen so random-access reads over large
+/// files only have to touch a handful of chunks rather than the whole blob.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Storage for the bytes behind a regular file. Implementations translate
+/// between the logical (cleartext) byte stream the kernel sees and whatever
+/// representation actually sits on disk.
+pub trait Backend: Send + Sync {
+    /// Create an empty blob at `path`.
+    fn create(&self, path: &Path) -> io::Result<()>;
+    /// Logical (cleartext) size of the blob at `path`.
+    fn logical_len(&self, path: &Path) -> io::Result<u64>;
+    /// Read up to `size` cleartext bytes starting at logical `offset`.
+    fn read_at(&self, path: &Path, offset: u64, size: u32) -> io::Result<Vec<u8>>;
+    /// Write `data` at logical `offset`, growing the blob if necessary.
+    /// Returns the number of bytes written.
+    fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<usize>;
+}
+
+/// Stores bytes exactly as given - today's behavior, kept as the default.
+pub struct PassthroughBackend;
+
+impl Backend for PassthroughBackend {
+    fn create(&self, path: &Path) -> io::Result<()> {
+        File::create(path).map(|_| ())
+    }
+
+    fn logical_len(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn read_at(&self, path: &Path, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let file = File::open(path)?;
+        let mut buf = vec![0; size as usize];
+        let n = file.read_at(&mut buf, offset)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<usize> {
+        let file = fs::OpenOptions::new().write(true).open(path)?;
+        file.write_all_at(data, offset)?;
+        Ok(data.len())
+    }
+}
+
+/// Turns a logical chunk into the bytes stored on disk for it, and back.
+trait ChunkCodec: Send + Sync {
+    fn encode(&self, chunk: &[u8]) -> Vec<u8>;
+    fn decode(&self, frame: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+struct ZstdCodec;
+
+impl ChunkCodec for ZstdCodec {
+    fn encode(&self, chunk: &[u8]) -> Vec<u8> {
+        zstd::stream::encode_all(chunk, 0).expect("zstd compression is infallible in-memory")
+    }
+
+    fn decode(&self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        zstd::stream::decode_all(frame)
+    }
+}
+
+struct AeadCodec {
+    cipher: ChaCha20Poly1305,
+}
+
+impl AeadCodec {
+    fn new(key: &[u8; 32]) -> Self {
+        AeadCodec {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+}
+
+impl ChunkCodec for AeadCodec {
+    fn encode(&self, chunk: &[u8]) -> Vec<u8> {
+        let mut nonce_bytes = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce_bytes);
+        let nonce = Nonce::from_slice(&nonce_bytes);
+        let ciphertext = self
+            .cipher
+            .encrypt(nonce, chunk)
+            .expect("chacha20poly1305 encryption is infallible for chunk-sized input");
+        let mut frame = Vec::with_capacity(12 + ciphertext.len());
+        frame.extend_from_slice(&nonce_bytes);
+        frame.extend_from_slice(&ciphertext);
+        frame
+    }
+
+    fn decode(&self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        if frame.len() < 12 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated AEAD frame"));
+        }
+        let (nonce_bytes, ciphertext) = frame.split_at(12);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce_bytes), ciphertext)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "AEAD authentication failed"))
+    }
+}
+
+/// One entry of a blob's chunk index: where its frame sits in the file and
+/// how long it is on disk (its logical length is implied by its position
+/// and the blob's overall `logical_len`).
+struct ChunkIndexEntry {
+    offset: u64,
+    len: u32,
+}
+
+/// A blob as a `[u64 logical_len][u32 chunk_count][index entry, ...][frame,
+/// frame, ...]` file, where each index entry is `[u64 frame_offset][u32
+/// frame_len]` and each frame is the `frame_len` bytes a `ChunkCodec`
+/// produced for one `CHUNK_SIZE` slice of cleartext. The header makes
+/// `logical_len` an O(1) read, and the index lets `read_at`/`write_at` seek
+/// straight to the chunks overlapping the requested range instead of
+/// decoding the whole blob.
+struct ChunkedBackend<C: ChunkCodec> {
+    codec: C,
+}
+
+impl<C: ChunkCodec> ChunkedBackend<C> {
+    fn new(codec: C) -> Self {
+        ChunkedBackend { codec }
+    }
+
+    fn header_len(chunk_count: usize) -> u64 {
+        (8 + 4 + chunk_count * 12) as u64
+    }
+
+    fn read_header(file: &mut File) -> io::Result<(u64, Vec<ChunkIndexEntry>)> {
+        let mut len_bytes = [0u8; 8];
+        file.read_exact(&mut len_bytes)?;
+        let logical_len = u64::from_le_bytes(len_bytes);
+
+        let mut count_bytes = [0u8; 4];
+        file.read_exact(&mut count_bytes)?;
+        let chunk_count = u32::from_le_bytes(count_bytes) as usize;
+
+        let mut index = Vec::with_capacity(chunk_count);
+        for _ in 0..chunk_count {
+            let mut entry_bytes = [0u8; 12];
+            file.read_exact(&mut entry_bytes)?;
+            index.push(ChunkIndexEntry {
+                offset: u64::from_le_bytes(entry_bytes[0..8].try_into().unwrap()),
+                len: u32::from_le_bytes(entry_bytes[8..12].try_into().unwrap()),
+            });
+        }
+        Ok((logical_len, index))
+    }
+
+    /// Cleartext length of chunk `chunk_index` in a blob whose logical
+    /// length is `logical_len` (the last chunk is typically shorter than
+    /// `CHUNK_SIZE`).
+    fn chunk_logical_len(chunk_index: usize, logical_len: usize) -> usize {
+        let start = chunk_index * CHUNK_SIZE;
+        logical_len.saturating_sub(start).min(CHUNK_SIZE)
+    }
+
+    fn read_chunk(&self, file: &File, entry: &ChunkIndexEntry) -> io::Result<Vec<u8>> {
+        let mut frame = vec![0u8; entry.len as usize];
+        file.read_exact_at(&mut frame, entry.offset)?;
+        self.codec.decode(&frame)
+    }
+
+    /// Write a brand-new blob from scratch, encoding every chunk.
+    fn write_blob(&self, path: &Path, logical: &[u8]) -> io::Result<()> {
+        let frames: Vec<Vec<u8>> = logical
+            .chunks(CHUNK_SIZE)
+            .map(|chunk| self.codec.encode(chunk))
+            .collect();
+        self.write_frames(path, logical.len() as u64, &frames)
+    }
+
+    /// Assemble a blob file out of already-encoded frames, one per chunk in
+    /// order, recomputing the index to match. The swap goes through a tmp
+    /// file so a crash mid-write can't leave a half-written blob in place,
+    /// but that means the rename would otherwise replace the destination's
+    /// inode - and with it, the permissions/ownership the caller (e.g.
+    /// FUSE's `create`) already set up. Carry those over onto the tmp file
+    /// first, when there's an existing destination to carry them from.
+    fn write_frames(&self, path: &Path, logical_len: u64, frames: &[Vec<u8>]) -> io::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut out = File::create(&tmp_path)?;
+        out.write_all(&logical_len.to_le_bytes())?;
+        out.write_all(&(frames.len() as u32).to_le_bytes())?;
+        let mut offset = Self::header_len(frames.len());
+        for frame in frames {
+            out.write_all(&offset.to_le_bytes())?;
+            out.write_all(&(frame.len() as u32).to_le_bytes())?;
+            offset += frame.len() as u64;
+        }
+        for frame in frames {
+            out.write_all(frame)?;
+        }
+        drop(out);
+
+        if let Ok(original) = fs::metadata(path) {
+            fs::set_permissions(&tmp_path, original.permissions())?;
+            chown(&tmp_path, Some(original.uid()), Some(original.gid()))?;
+        }
+
+        fs::rename(&tmp_path, path)
+    }
+}
+
+impl<C: ChunkCodec> Backend for ChunkedBackend<C> {
+    fn create(&self, path: &Path) -> io::Result<()> {
+        self.write_blob(path, &[])
+    }
+
+    fn logical_len(&self, path: &Path) -> io::Result<u64> {
+        let mut file = File::open(path)?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+        Ok(u64::from_le_bytes(header))
+    }
+
+    fn read_at(&self, path: &Path, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let mut file = File::open(path)?;
+        let (logical_len, index) = Self::read_header(&mut file)?;
+        let logical_len = logical_len as usize;
+        let start = (offset as usize).min(logical_len);
+        let end = start.saturating_add(size as usize).min(logical_len);
+        if start >= end {
+            return Ok(Vec::new());
+        }
+
+        let chunk_start = start / CHUNK_SIZE;
+        let chunk_end = (end - 1) / CHUNK_SIZE;
+        let mut out = Vec::with_capacity(end - start);
+        for (i, entry) in index.iter().enumerate().take(chunk_end + 1).skip(chunk_start) {
+            let chunk = self.read_chunk(&file, entry)?;
+            let chunk_start_byte = i * CHUNK_SIZE;
+            let lo = start.saturating_sub(chunk_start_byte);
+            let hi = (end - chunk_start_byte).min(chunk.len());
+            out.extend_from_slice(&chunk[lo..hi]);
+        }
+        Ok(out)
+    }
+
+    fn write_at(&self, path: &Path, offset: u64, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let mut file = File::open(path)?;
+        let (logical_len, index) = Self::read_header(&mut file)?;
+        let logical_len = logical_len as usize;
+        let offset = offset as usize;
+        let write_end = offset + data.len();
+        let new_logical_len = logical_len.max(write_end);
+
+        let old_chunk_count = index.len();
+        let new_chunk_count = new_logical_len.div_ceil(CHUNK_SIZE);
+        let chunk_start = offset / CHUNK_SIZE;
+        let chunk_end = (write_end - 1) / CHUNK_SIZE;
+
+        let mut frames = Vec::with_capacity(new_chunk_count);
+        for i in 0..new_chunk_count {
+            let overlaps = i >= chunk_start && i <= chunk_end;
+            // An old last chunk shorter than CHUNK_SIZE that the blob now
+            // grows past must be zero-padded to its new length as well.
+            let grows = i < old_chunk_count
+                && Self::chunk_logical_len(i, logical_len) < Self::chunk_logical_len(i, new_logical_len);
+            if overlaps || grows {
+                // Overlaps the write or grows: decode (if it already
+                // existed), zero-pad, overlay `data` where it falls in this
+                // chunk, and re-encode just this chunk.
+                let chunk_start_byte = i * CHUNK_SIZE;
+                let mut chunk = if i < old_chunk_count {
+                    self.read_chunk(&file, &index[i])?
+                } else {
+                    Vec::new()
+                };
+                let chunk_new_len = Self::chunk_logical_len(i, new_logical_len);
+                if chunk.len() < chunk_new_len {
+                    chunk.resize(chunk_new_len, 0);
+                }
+                if overlaps {
+                    let overlay_start = offset.max(chunk_start_byte) - chunk_start_byte;
+                    let overlay_end = write_end.min(chunk_start_byte + CHUNK_SIZE) - chunk_start_byte;
+                    let data_start = chunk_start_byte + overlay_start - offset;
+                    chunk[overlay_start..overlay_end]
+                        .copy_from_slice(&data[data_start..data_start + (overlay_end - overlay_start)]);
+                }
+                frames.push(self.codec.encode(&chunk));
+            } else if i < old_chunk_count {
+                // Untouched by this write: carry its frame over unchanged,
+                // no decode/encode needed.
+                let entry = &index[i];
+                let mut frame = vec![0u8; entry.len as usize];
+                file.read_exact_at(&mut frame, entry.offset)?;
+                frames.push(frame);
+            } else {
+                // A hole between the old EOF and this write, beyond
+                // CHUNK_SIZE away: materialize it as zeros.
+                let len = Self::chunk_logical_len(i, new_logical_len);
+                frames.push(self.codec.encode(&vec![0u8; len]));
+            }
+        }
+
+        self.write_frames(path, new_logical_len as u64, &frames)?;
+        Ok(data.len())
+    }
+}
+
+pub fn passthrough() -> Box<dyn Backend> {
+    Box::new(PassthroughBackend)
+}
+
+pub fn zstd() -> Box<dyn Backend> {
+    Box::new(ChunkedBackend::new(ZstdCodec))
+}
+
+pub fn aead(key_file: &Path) -> io::Result<Box<dyn Backend>> {
+    let mut file = File::open(key_file)?;
+    let mut key = [0u8; 32];
+    file.read_exact(&mut key)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "key file must be exactly 32 bytes"))?;
+    if file.read(&mut [0u8; 1])? != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "key file must be exactly 32 bytes"));
+    }
+    Ok(Box::new(ChunkedBackend::new(AeadCodec::new(&key))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    static NEXT_TMP_ID: AtomicU32 = AtomicU32::new(0);
+
+    /// A fresh path under the system temp dir, cleaned up on drop.
+    struct TmpPath(std::path::PathBuf);
+
+    impl TmpPath {
+        fn new(tag: &str) -> Self {
+            let id = NEXT_TMP_ID.fetch_add(1, Ordering::SeqCst);
+            TmpPath(std::env::temp_dir().join(format!("fuser-lab-backend-test-{tag}-{id}")))
+        }
+    }
+
+    impl Drop for TmpPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn zstd_backend() -> ChunkedBackend<ZstdCodec> {
+        ChunkedBackend::new(ZstdCodec)
+    }
+
+    fn aead_backend() -> ChunkedBackend<AeadCodec> {
+        ChunkedBackend::new(AeadCodec::new(&[7u8; 32]))
+    }
+
+    fn round_trip_multi_chunk<C: ChunkCodec>(backend: ChunkedBackend<C>) {
+        let path = TmpPath::new("round-trip");
+        backend.create(&path.0).unwrap();
+
+        // More than one CHUNK_SIZE so create/write exercise several frames.
+        let data: Vec<u8> = (0..CHUNK_SIZE * 3 + 123).map(|i| (i % 251) as u8).collect();
+        assert_eq!(backend.write_at(&path.0, 0, &data).unwrap(), data.len());
+
+        assert_eq!(backend.logical_len(&path.0).unwrap(), data.len() as u64);
+        let read_back = backend.read_at(&path.0, 0, data.len() as u32).unwrap();
+        assert_eq!(read_back, data);
+    }
+
+    #[test]
+    fn zstd_round_trips_multi_chunk_write() {
+        round_trip_multi_chunk(zstd_backend());
+    }
+
+    #[test]
+    fn aead_round_trips_multi_chunk_write() {
+        round_trip_multi_chunk(aead_backend());
+    }
+
+    #[test]
+    fn read_across_chunk_boundary() {
+        let backend = zstd_backend();
+        let path = TmpPath::new("boundary");
+        backend.create(&path.0).unwrap();
+
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2).map(|i| (i % 256) as u8).collect();
+        backend.write_at(&path.0, 0, &data).unwrap();
+
+        // Straddles the boundary between chunk 0 and chunk 1.
+        let start = CHUNK_SIZE - 16;
+        let len = 32;
+        let got = backend.read_at(&path.0, start as u64, len as u32).unwrap();
+        assert_eq!(got, data[start..start + len]);
+    }
+
+    #[test]
+    fn write_modifies_one_chunk_without_disturbing_others() {
+        let backend = zstd_backend();
+        let path = TmpPath::new("partial-write");
+        backend.create(&path.0).unwrap();
+
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2).map(|_| 0xAAu8).collect();
+        backend.write_at(&path.0, 0, &data).unwrap();
+
+        // Overwrite a few bytes in the middle of the second chunk only.
+        let patch = [0x11u8, 0x22, 0x33];
+        let patch_offset = CHUNK_SIZE + 10;
+        backend
+            .write_at(&path.0, patch_offset as u64, &patch)
+            .unwrap();
+
+        let whole = backend.read_at(&path.0, 0, data.len() as u32).unwrap();
+        assert_eq!(&whole[patch_offset..patch_offset + patch.len()], &patch);
+        assert_eq!(&whole[..patch_offset], &data[..patch_offset]);
+        assert_eq!(&whole[patch_offset + patch.len()..], &data[patch_offset + patch.len()..]);
+    }
+
+    #[test]
+    fn write_past_eof_extends_with_zeros() {
+        let backend = zstd_backend();
+        let path = TmpPath::new("extend");
+        backend.create(&path.0).unwrap();
+
+        backend.write_at(&path.0, CHUNK_SIZE as u64 + 5, b"hi").unwrap();
+
+        assert_eq!(backend.logical_len(&path.0).unwrap(), CHUNK_SIZE as u64 + 7);
+        let whole = backend
+            .read_at(&path.0, 0, (CHUNK_SIZE + 7) as u32)
+            .unwrap();
+        assert_eq!(&whole[..CHUNK_SIZE], vec![0u8; CHUNK_SIZE].as_slice());
+        assert_eq!(&whole[CHUNK_SIZE..CHUNK_SIZE + 5], &[0, 0, 0, 0, 0]);
+        assert_eq!(&whole[CHUNK_SIZE + 5..], b"hi");
+    }
+
+    #[test]
+    fn write_preserves_existing_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let backend = zstd_backend();
+        let path = TmpPath::new("perm-preserve");
+        backend.create(&path.0).unwrap();
+        fs::set_permissions(&path.0, fs::Permissions::from_mode(0o600)).unwrap();
+
+        // A blob write goes through the tmp-file-then-rename swap; it must
+        // not reset the destination back to the process's default create
+        // mode in the process.
+        backend.write_at(&path.0, 0, b"secret").unwrap();
+
+        let mode = fs::metadata(&path.0).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o600);
+    }
+}
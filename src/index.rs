@@ -0,0 +1,183 @@
+//! On-disk metadata cache index.
+//!
+//! `fuser::FileAttr`/`FileType` aren't `Serialize`, so we mirror them with
+//! `#[serde(remote = "...")]` shims and persist a flat list of
+//! inode/path/layer/attr entries, zstd-compressed, inside the first source
+//! directory.
+
+use fuser::{FileAttr, FileType};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub const INDEX_FILE_NAME: &str = "simple-fs.tree.zst";
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "FileType")]
+enum FileTypeDef {
+    NamedPipe,
+    CharDevice,
+    BlockDevice,
+    Directory,
+    RegularFile,
+    Symlink,
+    Socket,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "FileAttr")]
+struct FileAttrDef {
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    pub crtime: SystemTime,
+    #[serde(with = "FileTypeDef")]
+    pub kind: FileType,
+    pub perm: u16,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u32,
+    pub blksize: u32,
+    pub flags: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub path: PathBuf,
+    /// Which `source_dirs` entry this inode's content/attrs resolved from,
+    /// so a reload of a multi-directory overlay doesn't have to guess.
+    pub layer: usize,
+    #[serde(with = "FileAttrDef")]
+    pub attr: FileAttr,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Index {
+    pub entries: Vec<Entry>,
+}
+
+/// Load and decompress the index at `path`, if it exists.
+pub fn load(path: &Path) -> io::Result<Index> {
+    let compressed = std::fs::File::open(path)?;
+    let decompressed = zstd::stream::decode_all(compressed)?;
+    bincode::deserialize(&decompressed)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Compress and write `index` to `path`, replacing any existing file.
+pub fn save(path: &Path, index: &Index) -> io::Result<()> {
+    let encoded =
+        bincode::serialize(index).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let compressed = zstd::stream::encode_all(encoded.as_slice(), 0)?;
+    std::fs::write(path, compressed)
+}
+
+/// Whether the index at `index_path` exists and is newer than every entry
+/// in `source_dirs`, i.e. it's safe to trust without rescanning any layer.
+pub fn is_fresh<P: AsRef<Path>>(index_path: &Path, source_dirs: &[P]) -> bool {
+    let index_mtime = match std::fs::metadata(index_path).and_then(|md| md.modified()) {
+        Ok(mtime) => mtime,
+        Err(_) => return false,
+    };
+    source_dirs.iter().all(|source_dir| {
+        std::fs::metadata(source_dir)
+            .and_then(|md| md.modified())
+            .is_ok_and(|source_mtime| index_mtime >= source_mtime)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    static NEXT_TMP_ID: AtomicU32 = AtomicU32::new(0);
+
+    fn tmp_path() -> PathBuf {
+        let id = NEXT_TMP_ID.fetch_add(1, Ordering::SeqCst);
+        std::env::temp_dir().join(format!("fuser-lab-index-test-{id}"))
+    }
+
+    fn tmp_dir() -> PathBuf {
+        let path = tmp_path();
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn sample_attr(ino: u64) -> FileAttr {
+        FileAttr {
+            ino,
+            size: 42,
+            blocks: 1,
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::UNIX_EPOCH,
+            ctime: SystemTime::UNIX_EPOCH,
+            crtime: SystemTime::UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: 1000,
+            gid: 1000,
+            rdev: 0,
+            blksize: 512,
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn save_load_round_trip_preserves_layer() {
+        let path = tmp_path();
+        let index = Index {
+            entries: vec![
+                Entry {
+                    path: PathBuf::from("from-overrides"),
+                    layer: 0,
+                    attr: sample_attr(2),
+                },
+                Entry {
+                    path: PathBuf::from("from-base"),
+                    layer: 1,
+                    attr: sample_attr(3),
+                },
+            ],
+        };
+
+        save(&path, &index).unwrap();
+        let loaded = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.entries.len(), 2);
+        assert_eq!(loaded.entries[0].path, PathBuf::from("from-overrides"));
+        assert_eq!(loaded.entries[0].layer, 0);
+        assert_eq!(loaded.entries[1].path, PathBuf::from("from-base"));
+        assert_eq!(loaded.entries[1].layer, 1);
+    }
+
+    /// A multi-directory overlay must be rescanned if *any* layer changed,
+    /// not just the first one - the index covers every layer's contents.
+    #[test]
+    fn is_fresh_checks_every_layer() {
+        let layer0 = tmp_dir();
+        let layer1 = tmp_dir();
+        let index_path = tmp_path();
+        std::fs::write(&index_path, b"index").unwrap();
+
+        assert!(is_fresh(&index_path, &[&layer0, &layer1]));
+
+        // A change in the second (lower-priority) layer alone must be
+        // enough to invalidate the index.
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        std::fs::write(layer1.join("new-file"), b"x").unwrap();
+
+        assert!(!is_fresh(&index_path, &[&layer0, &layer1]));
+
+        std::fs::remove_file(&index_path).unwrap();
+        std::fs::remove_dir_all(&layer0).unwrap();
+        std::fs::remove_dir_all(&layer1).unwrap();
+    }
+}
@@ -3,6 +3,8 @@ use fuser::MountOption;
 use simple::SimpleFS;
 use tracing_subscriber;
 
+mod backend;
+mod index;
 mod simple;
 fn setup_logging(level: &str) {
     let level = match level {
@@ -21,9 +23,19 @@ fn main() {
     let matches =
         Command::new("simple")
             .version(crate_version!())
-            .arg(Arg::new("SOURCE_DIRECTORY").required(true).index(1).help(
-                "Source directory. Typically a local filesystem that actually holds the files.",
-            ))
+            .arg(
+                Arg::new("SOURCE_DIRECTORIES")
+                    .required(true)
+                    .num_args(1..)
+                    .index(1)
+                    .help(
+                        "One or more source directories, typically local filesystems that \
+                         actually hold the files. Given more than one, they're overlaid \
+                         together: earlier directories shadow files of the same name in \
+                         later ones, and the mount is forced read-only since there's no \
+                         single layer a write against the merged tree should land in.",
+                    ),
+            )
             .arg(
                 Arg::new("MOUNT_POINT")
                     .required(true)
@@ -48,15 +60,61 @@ fn main() {
                     .action(ArgAction::SetTrue)
                     .help("Allow root user to access filesystem"),
             )
+            .arg(
+                Arg::new("rw")
+                    .long("rw")
+                    .action(ArgAction::SetTrue)
+                    .help("Mount read-write instead of the read-only default"),
+            )
+            .arg(
+                Arg::new("rebuild-index")
+                    .long("rebuild-index")
+                    .action(ArgAction::SetTrue)
+                    .help("Ignore any cached simple-fs.tree.zst index and rescan the source directory"),
+            )
+            .arg(
+                Arg::new("compress")
+                    .long("compress")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("key-file")
+                    .help("Store backing file contents zstd-compressed on disk"),
+            )
+            .arg(
+                Arg::new("key-file")
+                    .long("key-file")
+                    .help("Store backing file contents ChaCha20-Poly1305 encrypted, keyed by this 32-byte file"),
+            )
             .get_matches();
     env_logger::init();
-    let source_dir = matches.get_one::<String>("SOURCE_DIRECTORY").unwrap();
+    let source_dirs: Vec<String> = matches
+        .get_many::<String>("SOURCE_DIRECTORIES")
+        .unwrap()
+        .cloned()
+        .collect();
     let mountpoint = matches.get_one::<String>("MOUNT_POINT").unwrap();
 
     let log_level = matches.get_one::<String>("LOG_LEVEL").unwrap();
     setup_logging(&log_level);
 
-    let mut options = vec![MountOption::RO, MountOption::FSName("simple".to_string())];
+    let rw = matches.get_flag("rw");
+    let rebuild_index = matches.get_flag("rebuild-index");
+
+    let storage_backend = if let Some(key_file) = matches.get_one::<String>("key-file") {
+        backend::aead(std::path::Path::new(key_file)).expect("failed to load key file")
+    } else if matches.get_flag("compress") {
+        backend::zstd()
+    } else {
+        backend::passthrough()
+    };
+
+    let fs = SimpleFS::new(source_dirs, rw, rebuild_index, storage_backend);
+
+    // Ask the filesystem for its effective rw-ness rather than trusting the
+    // raw --rw flag: SimpleFS::new downgrades it to read-only for overlay
+    // mounts, and the advertised mount mode needs to match what it actually
+    // enforces.
+    let mut options = vec![MountOption::FSName("simple".to_string())];
+    options.push(if fs.is_rw() { MountOption::RW } else { MountOption::RO });
     if matches.get_flag("auto_unmount") {
         options.push(MountOption::AutoUnmount);
     }
@@ -64,5 +122,5 @@ fn main() {
         options.push(MountOption::AllowRoot);
     }
 
-    fuser::mount2(SimpleFS::new(source_dir.to_owned()), mountpoint, &options).unwrap();
+    fuser::mount2(fs, mountpoint, &options).unwrap();
 }
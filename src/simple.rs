@@ -1,36 +1,152 @@
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyWrite, Request, TimeOrNow,
 };
-use libc::ENOENT;
+use libc::{EACCES, ENOENT};
 use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs::{self, File};
+use std::io;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::{DirEntryExt, FileExt, MetadataExt};
-use std::sync::atomic::AtomicUsize;
+use std::os::unix::fs::{chown, DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize};
 use std::sync::RwLock;
-use std::time::{Duration, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use tracing::{error, trace};
+use tracing::{error, info, trace};
+
+use crate::backend::Backend;
+use crate::index::{self, Index};
 
 const TTL: Duration = Duration::from_secs(1); // 1 second
 
+const ROOT_INO: u64 = 1;
+
 static NEXT_FH_ID: AtomicUsize = AtomicUsize::new(1);
+static NEXT_INO: AtomicU64 = AtomicU64::new(ROOT_INO + 1);
 
 pub struct SimpleFS {
-    source_dir: String, // source directory
-    inodes: RwLock<HashMap<u64, String>>,
-    file_handles: RwLock<HashMap<u64, File>>,
+    source_dirs: Vec<String>, // backing directories, highest priority first
+    rw: bool,                 // whether mutating operations are permitted
+    backend: Box<dyn Backend>,
+    // inode -> (layer the inode's own content/attrs were resolved from, path
+    // relative to every source_dirs entry). Directories are a union across
+    // all layers at that relative path, so the stored layer only matters for
+    // the directory's own attrs, not its children.
+    inodes: RwLock<HashMap<u64, (usize, PathBuf)>>,
+    paths: RwLock<HashMap<PathBuf, u64>>,
+    file_handles: RwLock<HashMap<u64, (usize, PathBuf)>>,
+    attr_cache: RwLock<HashMap<u64, (FileAttr, Instant)>>,
 }
 
 impl SimpleFS {
-    pub fn new(source_dir: String) -> Self {
-        let mut inodes: HashMap<u64, String> = HashMap::new();
-        inodes.insert(1, ".".into());
+    pub fn new(
+        source_dirs: Vec<String>,
+        rw: bool,
+        rebuild_index: bool,
+        backend: Box<dyn Backend>,
+    ) -> Self {
+        // Copy-up isn't implemented, so a multi-layer overlay is read-only:
+        // there's no single layer a write against a merged directory should
+        // land in.
+        let rw = rw && source_dirs.len() == 1;
+
+        let mut inodes: HashMap<u64, (usize, PathBuf)> = HashMap::new();
+        let mut paths: HashMap<PathBuf, u64> = HashMap::new();
+        let mut attr_cache: HashMap<u64, (FileAttr, Instant)> = HashMap::new();
+        inodes.insert(ROOT_INO, (0, PathBuf::from(".")));
+        paths.insert(PathBuf::from("."), ROOT_INO);
+
+        let index_path = Path::new(&source_dirs[0]).join(index::INDEX_FILE_NAME);
+        if !rebuild_index && index::is_fresh(&index_path, &source_dirs) {
+            match index::load(&index_path) {
+                Ok(index) => {
+                    info!("prewarming inode table from {:?}", index_path);
+                    let mut max_ino = ROOT_INO;
+                    let now = Instant::now();
+                    for entry in index.entries {
+                        max_ino = max_ino.max(entry.attr.ino);
+                        paths.insert(entry.path.clone(), entry.attr.ino);
+                        inodes.insert(entry.attr.ino, (entry.layer, entry.path));
+                        attr_cache.insert(entry.attr.ino, (entry.attr, now));
+                    }
+                    NEXT_INO.store(max_ino + 1, std::sync::atomic::Ordering::SeqCst);
+                }
+                Err(error) => error!("failed to load {:?}: {}", index_path, error),
+            }
+        }
+
         SimpleFS {
-            source_dir,
+            source_dirs,
+            rw,
+            backend,
             inodes: RwLock::new(inodes),
+            paths: RwLock::new(paths),
             file_handles: RwLock::new(HashMap::new()),
+            attr_cache: RwLock::new(attr_cache),
+        }
+    }
+
+    fn index_path(&self) -> PathBuf {
+        Path::new(&self.source_dirs[0]).join(index::INDEX_FILE_NAME)
+    }
+
+    /// Persist the current inode table and attribute cache so the next mount
+    /// can skip rescanning the source directory.
+    fn save_index(&self) {
+        let inodes = self.inodes.read().unwrap();
+        let attr_cache = self.attr_cache.read().unwrap();
+        let entries = inodes
+            .iter()
+            .filter_map(|(ino, (layer, path))| {
+                attr_cache.get(ino).map(|(attr, _)| index::Entry {
+                    path: path.clone(),
+                    layer: *layer,
+                    attr: *attr,
+                })
+            })
+            .collect();
+        drop(attr_cache);
+        drop(inodes);
+        if let Err(error) = index::save(&self.index_path(), &Index { entries }) {
+            error!("failed to save index: {}", error);
+        }
+    }
+
+    fn cached_attr(&self, ino: u64) -> Option<FileAttr> {
+        let cache = self.attr_cache.read().unwrap();
+        match cache.get(&ino) {
+            Some((attr, cached_at)) if cached_at.elapsed() < TTL => Some(*attr),
+            _ => None,
+        }
+    }
+
+    fn cache_attr(&self, attr: FileAttr) {
+        self.attr_cache
+            .write()
+            .unwrap()
+            .insert(attr.ino, (attr, Instant::now()));
+    }
+
+    /// Forget the path<->inode mapping for `path`, e.g. after an unlink or
+    /// rmdir. Leaves any already-allocated descendant inodes in place since
+    /// they're unreachable once their parent is gone.
+    fn forget_path(&self, path: &Path) {
+        if let Some(ino) = self.paths.write().unwrap().remove(path) {
+            self.inodes.write().unwrap().remove(&ino);
+        }
+    }
+
+    /// Re-point the inode for `from` (if any) at `to`, used after a rename.
+    fn remap_path(&self, from: &Path, to: &Path) {
+        if let Some(ino) = self.paths.write().unwrap().remove(from) {
+            let layer = self.inodes.read().unwrap().get(&ino).map_or(0, |(l, _)| *l);
+            self.inodes
+                .write()
+                .unwrap()
+                .insert(ino, (layer, to.to_path_buf()));
+            self.paths.write().unwrap().insert(to.to_path_buf(), ino);
         }
     }
 
@@ -38,14 +154,74 @@ impl SimpleFS {
         NEXT_FH_ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst) as u64 + 1
     }
 
-    fn local_path(&self, path: &OsStr) -> String {
-        format!("{}/{}", self.source_dir, path.to_string_lossy())
+    /// Whether mutating ops are actually permitted, after the overlay
+    /// downgrade above - the source of truth for the `MountOption::RW`/`RO`
+    /// flag the mount is advertised with, since the raw `--rw` CLI flag
+    /// alone doesn't account for it.
+    pub fn is_rw(&self) -> bool {
+        self.rw
+    }
+
+    fn next_ino(&self) -> u64 {
+        NEXT_INO.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// Resolve `path` (relative to every entry in `source_dirs`) to its
+    /// inode, allocating a fresh one the first time it's seen. The mapping
+    /// is stable for the lifetime of the mount, so repeated lookups of the
+    /// same path always return the same inode. `layer` records which
+    /// backing directory this particular resolution's content came from.
+    fn ino_for_path(&self, path: &Path, layer: usize) -> u64 {
+        if let Some(ino) = self.paths.read().unwrap().get(path) {
+            self.inodes
+                .write()
+                .unwrap()
+                .insert(*ino, (layer, path.to_path_buf()));
+            return *ino;
+        }
+        let ino = self.next_ino();
+        self.inodes
+            .write()
+            .unwrap()
+            .insert(ino, (layer, path.to_path_buf()));
+        self.paths.write().unwrap().insert(path.to_path_buf(), ino);
+        ino
+    }
+
+    fn path_for_ino(&self, ino: u64) -> Option<(usize, PathBuf)> {
+        self.inodes.read().unwrap().get(&ino).cloned()
+    }
+
+    fn local_path(&self, layer: usize, relative: &Path) -> PathBuf {
+        Path::new(&self.source_dirs[layer]).join(relative)
+    }
+
+    /// First layer (in priority order) whose backing directory has
+    /// something at `path`, used to pick whose attrs represent a directory
+    /// that may exist in several layers.
+    fn resolve_layer(&self, path: &Path) -> Option<usize> {
+        (0..self.source_dirs.len()).find(|&layer| self.local_path(layer, path).exists())
     }
 
-    fn file_attributes(&self, md: &fs::Metadata) -> FileAttr {
+    fn file_attributes(&self, ino: u64, local_path: &Path, md: &fs::Metadata) -> FileAttr {
+        // For symlinks `md.size()` is the length of the link's own inode
+        // data, not its target text, so report the target length instead -
+        // that's what readers actually expect `stat` to say. For regular
+        // files the backend may store something other than cleartext bytes
+        // (compressed/encrypted chunks), so ask it for the logical size
+        // rather than trusting the on-disk blob's length.
+        let size = if md.file_type().is_symlink() {
+            fs::read_link(local_path)
+                .map(|target| target.as_os_str().len() as u64)
+                .unwrap_or(0)
+        } else if md.is_file() {
+            self.backend.logical_len(local_path).unwrap_or_else(|_| md.size())
+        } else {
+            md.size()
+        };
         FileAttr {
-            ino: md.ino(),
-            size: md.size(),
+            ino,
+            size,
             blocks: md.blocks(),
             atime: UNIX_EPOCH,
             mtime: UNIX_EPOCH,
@@ -65,10 +241,99 @@ impl SimpleFS {
     fn file_type(&self, md: &fs::Metadata) -> FileType {
         if md.is_dir() {
             FileType::Directory
+        } else if md.file_type().is_symlink() {
+            FileType::Symlink
         } else {
             FileType::RegularFile
         }
     }
+
+    /// Inode to report for the `..` entry of the directory at `path`/`ino`.
+    /// The root is its own parent.
+    fn parent_ino(&self, ino: u64, path: &Path) -> u64 {
+        if ino == ROOT_INO {
+            return ROOT_INO;
+        }
+        match path.parent() {
+            Some(parent) if parent.as_os_str().is_empty() => {
+                let layer = self.resolve_layer(Path::new(".")).unwrap_or(0);
+                self.ino_for_path(Path::new("."), layer)
+            }
+            Some(parent) => {
+                let layer = self.resolve_layer(parent).unwrap_or(0);
+                self.ino_for_path(parent, layer)
+            }
+            None => ROOT_INO,
+        }
+    }
+
+    /// Build the `.`/`..`/children listing for the directory at `ino`,
+    /// allocating an inode for each child as it's discovered. Shared by
+    /// `readdir` (which only needs the file type) and `readdirplus` (which
+    /// needs the full `FileAttr`), so both stay in sync with each other.
+    ///
+    /// Unlike file lookups, which bind to a single winning layer, a
+    /// directory's listing is unioned across every layer that has a
+    /// directory at this relative path, with earlier layers shadowing
+    /// later ones for same-named entries - that's the whole point of the
+    /// overlay.
+    fn directory_listing(
+        &self,
+        ino: u64,
+    ) -> io::Result<Vec<(u64, std::ffi::OsString, FileAttr)>> {
+        let (_, dir_path) = self
+            .path_for_ino(ino)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+
+        let dot_layer = self.resolve_layer(&dir_path).unwrap_or(0);
+        let dot_local_path = self.local_path(dot_layer, &dir_path);
+        let dot_attr =
+            self.file_attributes(ino, &dot_local_path, &fs::symlink_metadata(&dot_local_path)?);
+
+        let parent = self.parent_ino(ino, &dir_path);
+        let (parent_layer, parent_path) = self
+            .path_for_ino(parent)
+            .unwrap_or((dot_layer, dir_path.clone()));
+        let parent_local_path = self.local_path(parent_layer, &parent_path);
+        let dotdot_attr = self.file_attributes(
+            parent,
+            &parent_local_path,
+            &fs::symlink_metadata(&parent_local_path)?,
+        );
+
+        let mut listing = vec![
+            (ino, std::ffi::OsString::from("."), dot_attr),
+            (parent, std::ffi::OsString::from(".."), dotdot_attr),
+        ];
+
+        let mut seen = std::collections::HashSet::new();
+        for layer in 0..self.source_dirs.len() {
+            let entries = match fs::read_dir(self.local_path(layer, &dir_path)) {
+                Ok(entries) => entries,
+                Err(_) => continue, // this layer doesn't have this directory
+            };
+            for entry in entries {
+                let entry = entry?;
+                if !seen.insert(entry.file_name()) {
+                    continue; // shadowed by a higher-priority layer
+                }
+                let child_path = dir_path.join(entry.file_name());
+                let child_local_path = self.local_path(layer, &child_path);
+                let md = match fs::symlink_metadata(&child_local_path) {
+                    Ok(md) => md,
+                    Err(error) => {
+                        error!("directory_listing: stat error for {:?}: {}", child_path, error);
+                        continue;
+                    }
+                };
+                let child_ino = self.ino_for_path(&child_path, layer);
+                let attr = self.file_attributes(child_ino, &child_local_path, &md);
+                listing.push((child_ino, entry.file_name(), attr));
+            }
+        }
+
+        Ok(listing)
+    }
 }
 
 impl Filesystem for SimpleFS {
@@ -78,37 +343,49 @@ impl Filesystem for SimpleFS {
             parent,
             name.to_string_lossy()
         );
-        if parent != 1 {
-            // we do not support directories
-            error!("sub-directories are not supported");
-            reply.error(ENOENT);
-            return;
-        }
 
-        let md_result = fs::metadata(self.local_path(name));
-        match md_result {
-            Ok(md) => {
-                let attr = self.file_attributes(&md);
-                self.inodes
-                    .write()
-                    .unwrap()
-                    .insert(attr.ino, name.to_string_lossy().into());
-                reply.entry(&TTL, &attr, 0);
-            }
-            Err(err) => {
-                error!("lookup error: {}", err);
+        let parent_path = match self.path_for_ino(parent) {
+            Some((_, path)) => path,
+            None => {
+                error!("lookup: unknown parent inode {}", parent);
                 reply.error(ENOENT);
+                return;
+            }
+        };
+        let child_path = parent_path.join(name);
+
+        for layer in 0..self.source_dirs.len() {
+            let local_path = self.local_path(layer, &child_path);
+            let md = match fs::symlink_metadata(&local_path) {
+                Ok(md) => md,
+                Err(_) => continue, // not in this layer, try the next
+            };
+            let ino = self.ino_for_path(&child_path, layer);
+            if let Some(attr) = self.cached_attr(ino) {
+                reply.entry(&TTL, &attr, 0);
+                return;
             }
+            let attr = self.file_attributes(ino, &local_path, &md);
+            self.cache_attr(attr);
+            reply.entry(&TTL, &attr, 0);
+            return;
         }
+        error!("lookup: {:?} not found in any layer", child_path);
+        reply.error(ENOENT);
     }
 
     fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
         trace!("getattr(ino={})", ino);
 
-        match self.inodes.read().unwrap().get(&ino) {
-            Some(name) => {
-                let local_path = self.local_path(&OsStr::from_bytes(name.as_bytes()));
-                let md = match fs::metadata(local_path) {
+        if let Some(attr) = self.cached_attr(ino) {
+            reply.attr(&TTL, &attr);
+            return;
+        }
+
+        match self.path_for_ino(ino) {
+            Some((layer, path)) => {
+                let local_path = self.local_path(layer, &path);
+                let md = match fs::symlink_metadata(&local_path) {
                     Ok(md) => md,
                     Err(err) => {
                         error!("getattr error: {}", err);
@@ -116,9 +393,10 @@ impl Filesystem for SimpleFS {
                         return;
                     }
                 };
-                trace!("metadata for {}: {:?}", name, md);
-                let file_attributes = self.file_attributes(&md);
-                trace!("file attributes for {}: {:?}", name, file_attributes);
+                trace!("metadata for {:?}: {:?}", path, md);
+                let file_attributes = self.file_attributes(ino, &local_path, &md);
+                self.cache_attr(file_attributes);
+                trace!("file attributes for {:?}: {:?}", path, file_attributes);
                 reply.attr(&TTL, &file_attributes);
             }
             None => reply.error(ENOENT),
@@ -126,21 +404,16 @@ impl Filesystem for SimpleFS {
     }
     fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
         trace!("open(ino={})", ino);
-        if let Some(name) = self.inodes.read().unwrap().get(&ino) {
-            let local_path = self.local_path(&OsStr::from_bytes(name.as_bytes()));
-            trace!("opening local path: {}", local_path);
-            let fh = match File::open(local_path) {
-                Ok(f) => {
-                    let fh = self.next_fh_id();
-                    self.file_handles.write().unwrap().insert(fh, f);
-                    fh
-                }
-                Err(error) => {
-                    error!("open error: {}", error);
-                    reply.error(ENOENT);
-                    return;
-                }
-            };
+        if let Some((layer, path)) = self.path_for_ino(ino) {
+            let local_path = self.local_path(layer, &path);
+            trace!("opening local path: {:?}", local_path);
+            if let Err(error) = fs::symlink_metadata(&local_path) {
+                error!("open error: {}", error);
+                reply.error(ENOENT);
+                return;
+            }
+            let fh = self.next_fh_id();
+            self.file_handles.write().unwrap().insert(fh, (layer, path));
             reply.opened(fh, 0);
         } else {
             reply.error(ENOENT);
@@ -182,30 +455,22 @@ impl Filesystem for SimpleFS {
             size
         );
 
-        if let Some(name) = self.inodes.read().unwrap().get(&ino) {
-            let local_path = self.local_path(&OsStr::from_bytes(name.as_bytes()));
-            trace!("reading local path: {}", local_path);
-            let file_handles = self.file_handles.read().unwrap();
-            let fh = file_handles.get(&fh);
-            let file = match fh {
-                Some(f) => f,
-                None => {
-                    error!("file not found");
-                    reply.error(ENOENT);
-                    return;
-                }
-            };
-            let mut buf = vec![0; size as usize];
-            match file.read_at(&mut buf, offset as u64) {
-                Ok(n) => reply.data(&buf[..n]),
-                Err(error) => {
-                    error!("read error: {}", error);
-                    reply.error(ENOENT);
-                    return;
-                }
-            };
-        } else {
-            reply.error(ENOENT);
+        let (layer, path) = match self.file_handles.read().unwrap().get(&fh).cloned() {
+            Some(entry) => entry,
+            None => {
+                error!("file not found");
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let local_path = self.local_path(layer, &path);
+        trace!("reading local path: {:?}", local_path);
+        match self.backend.read_at(&local_path, offset as u64, size) {
+            Ok(buf) => reply.data(&buf),
+            Err(error) => {
+                error!("read error: {}", error);
+                reply.error(ENOENT);
+            }
         }
     }
 
@@ -218,40 +483,757 @@ impl Filesystem for SimpleFS {
         mut reply: ReplyDirectory,
     ) {
         trace!("readdir(ino={}, offset={})", ino, offset);
-        if ino != 1 {
+        let listing = match self.directory_listing(ino) {
+            Ok(listing) => listing,
+            Err(error) => {
+                error!("readdir error: {}", error);
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        for (i, (child_ino, name, attr)) in listing.into_iter().enumerate().skip(offset as usize) {
+            trace!("adding entry: ino={} name={:?}", child_ino, name);
+            if reply.add(child_ino, (i + 1) as i64, attr.kind, &name) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+
+    fn readdirplus(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectoryPlus,
+    ) {
+        trace!("readdirplus(ino={}, offset={})", ino, offset);
+        let listing = match self.directory_listing(ino) {
+            Ok(listing) => listing,
+            Err(error) => {
+                error!("readdirplus error: {}", error);
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        for (i, (child_ino, name, attr)) in listing.into_iter().enumerate().skip(offset as usize) {
+            trace!("adding entry+: ino={} name={:?}", child_ino, name);
+            self.cache_attr(attr);
+            if reply.add(child_ino, (i + 1) as i64, &name, &TTL, &attr, 0) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        trace!("create(parent={}, name={:?})", parent, name);
+        if !self.rw {
+            reply.error(EACCES);
+            return;
+        }
+        let parent_path = match self.path_for_ino(parent) {
+            Some((_, path)) => path,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let child_path = parent_path.join(name);
+        let local_path = self.local_path(0, &child_path);
+
+        // Create the backing entry with the requested permissions first, so
+        // the backend (which only deals in bytes) doesn't need to know
+        // about mode/umask/flags.
+        if let Err(error) = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .mode(mode & !umask)
+            .custom_flags(flags)
+            .open(&local_path)
+        {
+            error!("create error: {}", error);
             reply.error(ENOENT);
             return;
         }
-        let entries = match fs::read_dir(&self.source_dir) {
-            Ok(res) => res,
+        if let Err(error) = self.backend.create(&local_path) {
+            error!("create: backend error: {}", error);
+            reply.error(ENOENT);
+            return;
+        }
+        let md = match fs::symlink_metadata(&local_path) {
+            Ok(md) => md,
             Err(error) => {
-                error!("readdir error: {}", error);
+                error!("create: stat error: {}", error);
                 reply.error(ENOENT);
                 return;
             }
         };
+        let ino = self.ino_for_path(&child_path, 0);
+        let attr = self.file_attributes(ino, &local_path, &md);
+        self.cache_attr(attr);
+        let fh = self.next_fh_id();
+        self.file_handles
+            .write()
+            .unwrap()
+            .insert(fh, (0, child_path));
+        reply.created(&TTL, &attr, 0, fh, 0);
+    }
 
-        for (i, entry) in entries.enumerate().skip(offset as usize) {
-            trace!("processing entry: {:?}", entry);
-            let entry = match entry {
-                Ok(entry) => entry,
-                Err(error) => {
-                    error!("readdir error: {}", error);
-                    reply.error(ENOENT);
-                    return;
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        trace!("write(ino={}, fh={}, offset={})", ino, fh, offset);
+        if !self.rw {
+            reply.error(EACCES);
+            return;
+        }
+        let (layer, path) = match self.file_handles.read().unwrap().get(&fh).cloned() {
+            Some(entry) => entry,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let local_path = self.local_path(layer, &path);
+        match self.backend.write_at(&local_path, offset as u64, data) {
+            Ok(n) => {
+                // The write may have changed the file's logical size, so
+                // the cached attrs (if any) are now stale - refresh them
+                // rather than let a stat() in the same TTL window see the
+                // pre-write size.
+                if let Ok(md) = fs::symlink_metadata(&local_path) {
+                    let attr = self.file_attributes(ino, &local_path, &md);
+                    self.cache_attr(attr);
                 }
-            };
+                reply.written(n as u32)
+            }
+            Err(error) => {
+                error!("write error: {}", error);
+                reply.error(ENOENT);
+            }
+        }
+    }
 
-            if reply.add(
-                entry.ino(),
-                (i + 1) as i64,
-                self.file_type(&entry.metadata().expect("could not read entry metadata")),
-                &entry.file_name(),
-            ) {
-                break;
+    fn mkdir(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        trace!("mkdir(parent={}, name={:?})", parent, name);
+        if !self.rw {
+            reply.error(EACCES);
+            return;
+        }
+        let parent_path = match self.path_for_ino(parent) {
+            Some((_, path)) => path,
+            None => {
+                reply.error(ENOENT);
+                return;
             }
+        };
+        let child_path = parent_path.join(name);
+        let local_path = self.local_path(0, &child_path);
+
+        if let Err(error) = fs::DirBuilder::new()
+            .mode(mode & !umask)
+            .create(&local_path)
+        {
+            error!("mkdir error: {}", error);
+            reply.error(ENOENT);
+            return;
         }
+        match fs::symlink_metadata(&local_path) {
+            Ok(md) => {
+                let ino = self.ino_for_path(&child_path, 0);
+                let attr = self.file_attributes(ino, &local_path, &md);
+                self.cache_attr(attr);
+                reply.entry(&TTL, &attr, 0);
+            }
+            Err(error) => {
+                error!("mkdir: stat error: {}", error);
+                reply.error(ENOENT);
+            }
+        }
+    }
 
-        reply.ok();
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        trace!("unlink(parent={}, name={:?})", parent, name);
+        if !self.rw {
+            reply.error(EACCES);
+            return;
+        }
+        let parent_path = match self.path_for_ino(parent) {
+            Some((_, path)) => path,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let child_path = parent_path.join(name);
+        match fs::remove_file(self.local_path(0, &child_path)) {
+            Ok(()) => {
+                self.forget_path(&child_path);
+                reply.ok();
+            }
+            Err(error) => {
+                error!("unlink error: {}", error);
+                reply.error(ENOENT);
+            }
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        trace!("rmdir(parent={}, name={:?})", parent, name);
+        if !self.rw {
+            reply.error(EACCES);
+            return;
+        }
+        let parent_path = match self.path_for_ino(parent) {
+            Some((_, path)) => path,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let child_path = parent_path.join(name);
+        match fs::remove_dir(self.local_path(0, &child_path)) {
+            Ok(()) => {
+                self.forget_path(&child_path);
+                reply.ok();
+            }
+            Err(error) => {
+                error!("rmdir error: {}", error);
+                reply.error(ENOENT);
+            }
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        trace!(
+            "rename(parent={}, name={:?}, newparent={}, newname={:?})",
+            parent,
+            name,
+            newparent,
+            newname
+        );
+        if !self.rw {
+            reply.error(EACCES);
+            return;
+        }
+        let (Some((_, parent_path)), Some((_, new_parent_path))) =
+            (self.path_for_ino(parent), self.path_for_ino(newparent))
+        else {
+            reply.error(ENOENT);
+            return;
+        };
+        let old_path = parent_path.join(name);
+        let new_path = new_parent_path.join(newname);
+        match fs::rename(self.local_path(0, &old_path), self.local_path(0, &new_path)) {
+            Ok(()) => {
+                self.remap_path(&old_path, &new_path);
+                reply.ok();
+            }
+            Err(error) => {
+                error!("rename error: {}", error);
+                reply.error(ENOENT);
+            }
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        trace!("setattr(ino={})", ino);
+        if !self.rw {
+            reply.error(EACCES);
+            return;
+        }
+        let path = match self.path_for_ino(ino) {
+            Some((_, path)) => path,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let local_path = self.local_path(0, &path);
+
+        if let Some(size) = size {
+            if let Err(error) = fs::OpenOptions::new()
+                .write(true)
+                .open(&local_path)
+                .and_then(|f| f.set_len(size))
+            {
+                error!("setattr: truncate error: {}", error);
+                reply.error(ENOENT);
+                return;
+            }
+        }
+        if let Some(mode) = mode {
+            if let Err(error) = fs::set_permissions(&local_path, fs::Permissions::from_mode(mode))
+            {
+                error!("setattr: chmod error: {}", error);
+                reply.error(ENOENT);
+                return;
+            }
+        }
+        if uid.is_some() || gid.is_some() {
+            if let Err(error) = chown(&local_path, uid, gid) {
+                error!("setattr: chown error: {}", error);
+                reply.error(ENOENT);
+                return;
+            }
+        }
+
+        match fs::symlink_metadata(&local_path) {
+            Ok(md) => {
+                let attr = self.file_attributes(ino, &local_path, &md);
+                self.cache_attr(attr);
+                reply.attr(&TTL, &attr);
+            }
+            Err(error) => {
+                error!("setattr: stat error: {}", error);
+                reply.error(ENOENT);
+            }
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        trace!("fsync(ino={}, fh={}, datasync={})", ino, fh, datasync);
+        let (layer, path) = match self.file_handles.read().unwrap().get(&fh).cloned() {
+            Some(entry) => entry,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let file = match File::open(self.local_path(layer, &path)) {
+            Ok(f) => f,
+            Err(error) => {
+                error!("fsync: open error: {}", error);
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let result = if datasync {
+            file.sync_data()
+        } else {
+            file.sync_all()
+        };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(error) => {
+                error!("fsync error: {}", error);
+                reply.error(ENOENT);
+            }
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: fuser::ReplyData) {
+        trace!("readlink(ino={})", ino);
+        let (layer, path) = match self.path_for_ino(ino) {
+            Some(entry) => entry,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        match fs::read_link(self.local_path(layer, &path)) {
+            Ok(target) => reply.data(target.as_os_str().as_bytes()),
+            Err(error) => {
+                error!("readlink error: {}", error);
+                reply.error(ENOENT);
+            }
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        trace!("symlink(parent={}, name={:?}, link={:?})", parent, name, link);
+        if !self.rw {
+            reply.error(EACCES);
+            return;
+        }
+        let parent_path = match self.path_for_ino(parent) {
+            Some((_, path)) => path,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let child_path = parent_path.join(name);
+        let local_path = self.local_path(0, &child_path);
+
+        if let Err(error) = std::os::unix::fs::symlink(link, &local_path) {
+            error!("symlink error: {}", error);
+            reply.error(ENOENT);
+            return;
+        }
+        match fs::symlink_metadata(&local_path) {
+            Ok(md) => {
+                let ino = self.ino_for_path(&child_path, 0);
+                let attr = self.file_attributes(ino, &local_path, &md);
+                self.cache_attr(attr);
+                reply.entry(&TTL, &attr, 0);
+            }
+            Err(error) => {
+                error!("symlink: stat error: {}", error);
+                reply.error(ENOENT);
+            }
+        }
+    }
+
+    fn destroy(&mut self) {
+        trace!("destroy");
+        self.save_index();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    static NEXT_TMP_ID: AtomicU32 = AtomicU32::new(0);
+
+    /// A fresh, empty temp directory, removed on drop.
+    struct TmpDir(PathBuf);
+
+    impl TmpDir {
+        fn new(tag: &str) -> Self {
+            let id = NEXT_TMP_ID.fetch_add(1, Ordering::SeqCst);
+            let path = std::env::temp_dir().join(format!("fuser-lab-simple-test-{tag}-{id}"));
+            fs::create_dir_all(&path).unwrap();
+            TmpDir(path)
+        }
+    }
+
+    impl Drop for TmpDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Overlay shadowing: a higher-priority layer's entry hides a
+    /// same-named entry in a lower-priority layer, but the lower layer's
+    /// own-only entries still show through.
+    #[test]
+    fn directory_listing_shadows_lower_layers() {
+        let overrides = TmpDir::new("overrides");
+        let base = TmpDir::new("base");
+        fs::write(overrides.0.join("shared.txt"), b"from overrides").unwrap();
+        fs::write(base.0.join("shared.txt"), b"from base").unwrap();
+        fs::write(base.0.join("base-only.txt"), b"base only").unwrap();
+
+        let sfs = SimpleFS::new(
+            vec![
+                overrides.0.to_str().unwrap().to_string(),
+                base.0.to_str().unwrap().to_string(),
+            ],
+            false,
+            true,
+            crate::backend::passthrough(),
+        );
+
+        let listing = sfs.directory_listing(ROOT_INO).unwrap();
+        let names: Vec<String> = listing
+            .iter()
+            .map(|(_, name, _)| name.to_string_lossy().into_owned())
+            .collect();
+        assert!(names.contains(&"shared.txt".to_string()));
+        assert!(names.contains(&"base-only.txt".to_string()));
+        // Only one "shared.txt" entry - the overrides layer's copy wins.
+        assert_eq!(names.iter().filter(|n| *n == "shared.txt").count(), 1);
+
+        let (layer, _) = sfs
+            .path_for_ino(
+                listing
+                    .iter()
+                    .find(|(_, name, _)| name == "shared.txt")
+                    .unwrap()
+                    .0,
+            )
+            .unwrap();
+        assert_eq!(layer, 0);
+    }
+
+    /// Regression test: listing a subdirectory that only exists in a
+    /// lower-priority layer must not corrupt the root's own layer binding,
+    /// since `parent_ino` has to resolve `..`'s layer from the parent path,
+    /// not the child it was called for.
+    #[test]
+    fn listing_lower_layer_subdir_preserves_root_layer() {
+        let overrides = TmpDir::new("overrides");
+        let base = TmpDir::new("base");
+        fs::create_dir(base.0.join("a")).unwrap();
+
+        let sfs = SimpleFS::new(
+            vec![
+                overrides.0.to_str().unwrap().to_string(),
+                base.0.to_str().unwrap().to_string(),
+            ],
+            false,
+            true,
+            crate::backend::passthrough(),
+        );
+
+        let root_listing = sfs.directory_listing(ROOT_INO).unwrap();
+        let a_ino = root_listing
+            .iter()
+            .find(|(_, name, _)| name == "a")
+            .unwrap()
+            .0;
+        assert_eq!(sfs.path_for_ino(a_ino).unwrap().0, 1);
+
+        sfs.directory_listing(a_ino).unwrap();
+
+        let (root_layer, _) = sfs.path_for_ino(ROOT_INO).unwrap();
+        assert_eq!(root_layer, 0);
+    }
+
+    /// The mutating ops (write/setattr/create/mkdir/symlink) recompute a
+    /// fresh `FileAttr` after changing the backing file and push it through
+    /// `cache_attr`. This checks that `cache_attr` replaces a stale cached
+    /// entry for the same inode, so a `stat()` within the TTL window after a
+    /// mutation sees the new attrs rather than the pre-mutation ones.
+    #[test]
+    fn cache_attr_overwrites_stale_entry() {
+        let dir = TmpDir::new("cache-overwrite");
+        let sfs = SimpleFS::new(
+            vec![dir.0.to_str().unwrap().to_string()],
+            true,
+            true,
+            crate::backend::passthrough(),
+        );
+
+        let file_path = dir.0.join("f");
+        fs::write(&file_path, b"12345").unwrap();
+        let ino = sfs.ino_for_path(Path::new("f"), 0);
+        let stale = sfs.file_attributes(
+            ino,
+            &file_path,
+            &fs::symlink_metadata(&file_path).unwrap(),
+        );
+        sfs.cache_attr(stale);
+        assert_eq!(sfs.cached_attr(ino).unwrap().size, 5);
+
+        // Simulate what `write` now does after extending the file: stat
+        // again and push the fresh attrs into the cache.
+        fs::write(&file_path, b"1234567890").unwrap();
+        let fresh = sfs.file_attributes(
+            ino,
+            &file_path,
+            &fs::symlink_metadata(&file_path).unwrap(),
+        );
+        sfs.cache_attr(fresh);
+
+        assert_eq!(sfs.cached_attr(ino).unwrap().size, 10);
+    }
+
+    /// `ino_for_path` must keep returning the same inode for the same
+    /// relative path across repeated resolutions, including paths nested
+    /// several directories deep - that's what lets the kernel's cached
+    /// dentries stay valid for the life of the mount.
+    #[test]
+    fn ino_for_path_is_stable_across_nested_lookups() {
+        let dir = TmpDir::new("nested-inodes");
+        fs::create_dir_all(dir.0.join("a/b")).unwrap();
+        fs::write(dir.0.join("a/b/c.txt"), b"leaf").unwrap();
+
+        let sfs = SimpleFS::new(
+            vec![dir.0.to_str().unwrap().to_string()],
+            false,
+            true,
+            crate::backend::passthrough(),
+        );
+
+        let a_ino = sfs.ino_for_path(Path::new("a"), 0);
+        let b_ino = sfs.ino_for_path(Path::new("a/b"), 0);
+        let c_ino = sfs.ino_for_path(Path::new("a/b/c.txt"), 0);
+
+        // Distinct paths get distinct inodes.
+        assert_ne!(a_ino, b_ino);
+        assert_ne!(b_ino, c_ino);
+
+        // Re-resolving the same paths returns the same inodes, not fresh
+        // ones.
+        assert_eq!(sfs.ino_for_path(Path::new("a"), 0), a_ino);
+        assert_eq!(sfs.ino_for_path(Path::new("a/b"), 0), b_ino);
+        assert_eq!(sfs.ino_for_path(Path::new("a/b/c.txt"), 0), c_ino);
+
+        assert_eq!(sfs.path_for_ino(c_ino).unwrap().1, Path::new("a/b/c.txt"));
+    }
+
+    /// The RW path is only ever offered for a single-directory mount -
+    /// `SimpleFS::new` must downgrade an overlay (2+ source_dirs) to
+    /// read-only regardless of the caller's requested `rw`, since there's
+    /// no single layer a write against the merged tree should land in.
+    #[test]
+    fn rw_is_downgraded_for_overlay_mounts() {
+        let single = TmpDir::new("rw-single");
+        let sfs = SimpleFS::new(
+            vec![single.0.to_str().unwrap().to_string()],
+            true,
+            true,
+            crate::backend::passthrough(),
+        );
+        assert!(sfs.is_rw());
+
+        let overrides = TmpDir::new("rw-overlay-overrides");
+        let base = TmpDir::new("rw-overlay-base");
+        let overlay = SimpleFS::new(
+            vec![
+                overrides.0.to_str().unwrap().to_string(),
+                base.0.to_str().unwrap().to_string(),
+            ],
+            true,
+            true,
+            crate::backend::passthrough(),
+        );
+        assert!(!overlay.is_rw());
+    }
+
+    /// `forget_path`/`remap_path` back `unlink`/`rmdir` and `rename`: a
+    /// forgotten path's inode mapping is fully gone, and a renamed path's
+    /// inode follows it to the new name rather than being reallocated.
+    #[test]
+    fn forget_and_remap_path_update_inode_table() {
+        let dir = TmpDir::new("forget-remap");
+        let sfs = SimpleFS::new(
+            vec![dir.0.to_str().unwrap().to_string()],
+            true,
+            true,
+            crate::backend::passthrough(),
+        );
+
+        let ino = sfs.ino_for_path(Path::new("old.txt"), 0);
+        sfs.remap_path(Path::new("old.txt"), Path::new("new.txt"));
+        assert_eq!(sfs.path_for_ino(ino).unwrap().1, Path::new("new.txt"));
+        // The old path no longer resolves to the same inode.
+        assert_ne!(sfs.ino_for_path(Path::new("old.txt"), 0), ino);
+
+        sfs.forget_path(Path::new("new.txt"));
+        assert!(sfs.path_for_ino(ino).is_none());
+    }
+
+    /// A symlink's reported `size` must be the length of its target text
+    /// (what `readlink`/`stat` on a real filesystem would show), not the
+    /// size of the link's own on-disk inode data that `Metadata::size`
+    /// returns for a symlink.
+    #[test]
+    fn symlink_size_is_target_length_not_link_inode_size() {
+        let dir = TmpDir::new("symlink-size");
+        std::os::unix::fs::symlink("some/target", dir.0.join("link")).unwrap();
+
+        let sfs = SimpleFS::new(
+            vec![dir.0.to_str().unwrap().to_string()],
+            false,
+            true,
+            crate::backend::passthrough(),
+        );
+
+        let link_path = dir.0.join("link");
+        let md = fs::symlink_metadata(&link_path).unwrap();
+        assert!(md.file_type().is_symlink());
+
+        let attr = sfs.file_attributes(1, &link_path, &md);
+        assert_eq!(attr.kind, FileType::Symlink);
+        assert_eq!(attr.size, "some/target".len() as u64);
+
+        let target = fs::read_link(&link_path).unwrap();
+        assert_eq!(target, Path::new("some/target"));
+    }
+
+    /// `directory_listing` backs both `readdir` and `readdirplus`, but only
+    /// `readdirplus` needs the full `FileAttr` per entry rather than just
+    /// the file type - check that a mixed file/subdirectory listing reports
+    /// correct `kind` and `size` for every entry, not just `.`/`..`.
+    #[test]
+    fn directory_listing_reports_full_attrs_for_readdirplus() {
+        let dir = TmpDir::new("readdirplus-attrs");
+        fs::write(dir.0.join("file.txt"), b"hello").unwrap();
+        fs::create_dir(dir.0.join("subdir")).unwrap();
+
+        let sfs = SimpleFS::new(
+            vec![dir.0.to_str().unwrap().to_string()],
+            false,
+            true,
+            crate::backend::passthrough(),
+        );
+
+        let listing = sfs.directory_listing(ROOT_INO).unwrap();
+
+        let (_, _, file_attr) = listing
+            .iter()
+            .find(|(_, name, _)| name == "file.txt")
+            .unwrap();
+        assert_eq!(file_attr.kind, FileType::RegularFile);
+        assert_eq!(file_attr.size, 5);
+
+        let (_, _, dir_attr) = listing
+            .iter()
+            .find(|(_, name, _)| name == "subdir")
+            .unwrap();
+        assert_eq!(dir_attr.kind, FileType::Directory);
+
+        let (_, _, dot_attr) = listing.iter().find(|(_, name, _)| name == ".").unwrap();
+        assert_eq!(dot_attr.kind, FileType::Directory);
     }
 }